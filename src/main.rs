@@ -6,13 +6,19 @@ use vulkano::{
     command_buffer::{AutoCommandBufferBuilder, CommandBuffer},
     descriptor::{descriptor_set::PersistentDescriptorSet, PipelineLayoutAbstract},
     device::Device,
-    format::Format,
+    format::{ClearValue, Format},
     image::{Dimensions, ImageUsage, StorageImage},
-    instance::{Instance, InstanceExtensions, PhysicalDevice},
+    instance::{
+        debug::{DebugCallback, MessageSeverity, MessageType},
+        Instance, InstanceExtensions, PhysicalDevice,
+    },
     pipeline::ComputePipeline,
     sampler::Filter,
-    swapchain::{ColorSpace, FullscreenExclusive, PresentMode, SurfaceTransform, Swapchain},
-    sync::GpuFuture,
+    swapchain::{
+        AcquireError, ColorSpace, FullscreenExclusive, PresentMode, SurfaceTransform, Swapchain,
+        SwapchainCreationError,
+    },
+    sync::{self, FenceSignalFuture, FlushError, GpuFuture},
 };
 use vulkano_win::VkSurfaceBuild;
 use winit::{
@@ -31,11 +37,40 @@ fn main() {
 
     let matches = app.get_matches();
 
+    let debug_enabled = matches.is_present("debug");
+
     let extensions = InstanceExtensions {
         khr_wayland_surface: true,
+        ext_debug_utils: debug_enabled,
         ..vulkano_win::required_extensions()
     };
-    let instance = Instance::new(None, &extensions, None).expect("failed to create instance");
+    let layer = if debug_enabled {
+        Some("VK_LAYER_KHRONOS_validation")
+    } else {
+        None
+    };
+    let instance = Instance::new(None, &extensions, layer).expect("failed to create instance");
+
+    // Installing a debug messenger is opt-in since it adds overhead and requires the
+    // validation layer to be present on the system.
+    let _debug_callback = if debug_enabled {
+        DebugCallback::new(
+            &instance,
+            MessageSeverity::errors_and_warnings() | MessageSeverity::information(),
+            MessageType::all(),
+            |msg| {
+                eprintln!(
+                    "[{:?}] {}: {}",
+                    msg.ty,
+                    msg.layer_prefix.unwrap_or("unknown"),
+                    msg.description
+                );
+            },
+        )
+        .ok()
+    } else {
+        None
+    };
 
     // Printing physical devices
     if matches.is_present("list-devices") {
@@ -80,7 +115,7 @@ fn main() {
 
     // Creating the window
 
-    let width = if let Some(w) = matches.value_of("width") {
+    let mut width = if let Some(w) = matches.value_of("width") {
         match w.parse::<u32>() {
             Ok(w) => w,
             Err(_) => {
@@ -91,7 +126,7 @@ fn main() {
     } else {
         500
     };
-    let height = if let Some(h) = matches.value_of("height") {
+    let mut height = if let Some(h) = matches.value_of("height") {
         match h.parse::<u32>() {
             Ok(h) => h,
             Err(_) => {
@@ -103,6 +138,30 @@ fn main() {
         500
     };
 
+    // Opt-in internal render resolution, decoupled from the window size via a blit. When
+    // absent (the common case) the compute shader writes straight into the swapchain image,
+    // provided the swapchain offers R8G8B8A8Unorm.
+    let internal_width: Option<u32> = match matches.value_of("internal-width") {
+        Some(w) => match w.parse() {
+            Ok(w) => Some(w),
+            Err(_) => {
+                eprintln!("Invalid argument provided. --internal-width must be an integer");
+                return;
+            }
+        },
+        None => None,
+    };
+    let internal_height: Option<u32> = match matches.value_of("internal-height") {
+        Some(h) => match h.parse() {
+            Ok(h) => Some(h),
+            Err(_) => {
+                eprintln!("Invalid argument provided. --internal-height must be an integer");
+                return;
+            }
+        },
+        None => None,
+    };
+
     // Picking a single queue for all operations
     let queue_family = physical
         .queue_families()
@@ -148,12 +207,157 @@ fn main() {
 
     let mut view_position = [0., 0., 0.];
     let mut view_angle = 0.0;
-    let mut moving_forward = false;
-    let mut moving_backward = false;
+    let mut view_pitch = 0.0;
+    // Up/W and Down/S each drive the same motion but are tracked as separate physical
+    // keys so releasing one doesn't stop movement while the other is still held.
+    let mut up_pressed = false;
+    let mut w_pressed = false;
+    let mut down_pressed = false;
+    let mut s_pressed = false;
     let mut turning_left = false;
     let mut turning_right = false;
+    let mut strafing_left = false;
+    let mut strafing_right = false;
+    let mut moving_up = false;
+    let mut moving_down = false;
+
+    const MOUSE_SENSITIVITY: f32 = 0.1;
+    const MAX_PITCH: f32 = 89.0;
+
+    if let Some(n) = matches.value_of("animate") {
+        // Exporting an animated sequence along a keyframed camera path
 
-    if let Some(output) = matches.value_of("frame") {
+        let frame_count: u32 = match n.parse() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("Invalid argument provided. --animate must be an integer");
+                return;
+            }
+        };
+        let fps: f32 = if let Some(f) = matches.value_of("fps") {
+            match f.parse() {
+                Ok(f) => f,
+                Err(_) => {
+                    eprintln!("Invalid argument provided. --fps must be a number");
+                    return;
+                }
+            }
+        } else {
+            30.0
+        };
+        let output_prefix = matches.value_of("frame").unwrap_or("output");
+
+        let keyframes_path = matches.value_of("keyframes").unwrap();
+        let keyframes_src = match std::fs::read_to_string(keyframes_path) {
+            Ok(src) => src,
+            Err(e) => {
+                eprintln!("Failed to read keyframes file {}: {}", keyframes_path, e);
+                return;
+            }
+        };
+        let mut keyframes = Vec::new();
+        for line in keyframes_src.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let values: Vec<f32> = line.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+            if values.len() != 4 {
+                eprintln!("Invalid keyframe line (expected \"x y z yaw\"): {}", line);
+                return;
+            }
+            keyframes.push(([values[0], values[1], values[2]], values[3]));
+        }
+        if keyframes.len() < 2 {
+            eprintln!("--keyframes must contain at least 2 keyframes");
+            return;
+        }
+
+        let buf = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage::all(),
+            false,
+            (0..width * height * 4).map(|_| 0u8),
+        )
+        .expect("failed to create buffer");
+        let layout = compute_pipeline.layout().descriptor_set_layout(0).unwrap();
+
+        // The time uniform is driven by --fps rather than wall-clock time so a render is
+        // reproducible regardless of how long the GPU takes per frame.
+        let segments = (keyframes.len() - 1) as f32;
+        for frame in 0..frame_count {
+            let t = if frame_count > 1 {
+                frame as f32 / (frame_count - 1) as f32
+            } else {
+                0.0
+            } * segments;
+            let segment = (t.floor() as usize).min(keyframes.len() - 2);
+            let local_t = t - segment as f32;
+            let (start_position, start_angle) = keyframes[segment];
+            let (end_position, end_angle) = keyframes[segment + 1];
+            let view_position = [
+                start_position[0] + (end_position[0] - start_position[0]) * local_t,
+                start_position[1] + (end_position[1] - start_position[1]) * local_t,
+                start_position[2] + (end_position[2] - start_position[2]) * local_t,
+            ];
+            let view_angle = start_angle + (end_angle - start_angle) * local_t;
+
+            let params_buffer = CpuAccessibleBuffer::from_data(
+                device.clone(),
+                BufferUsage::all(),
+                false,
+                cs::ty::Input {
+                    width: width as i32,
+                    height: height as i32,
+                    view_position,
+                    view_angle,
+                    view_pitch: 0.0,
+                    time: frame as f32 / fps,
+                    _dummy0: [0, 0, 0, 0, 0, 0, 0, 0],
+                },
+            )
+            .expect("failed to create params buffer");
+
+            let set = Arc::new(
+                PersistentDescriptorSet::start(layout.clone())
+                    .add_image(image.clone())
+                    .unwrap()
+                    .add_buffer(params_buffer)
+                    .unwrap()
+                    .build()
+                    .unwrap(),
+            );
+
+            let mut builder =
+                AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
+            builder
+                .dispatch(
+                    [(width + 7) / 8, (height + 7) / 8, 1],
+                    compute_pipeline.clone(),
+                    set.clone(),
+                    (),
+                )
+                .unwrap()
+                .copy_image_to_buffer(image.clone(), buf.clone())
+                .unwrap();
+            let command_buffer = builder.build().unwrap();
+
+            let finished = command_buffer.execute(queue.clone()).unwrap();
+            finished
+                .then_signal_fence_and_flush()
+                .unwrap()
+                .wait(None)
+                .unwrap();
+
+            let buffer_content = buf.read().unwrap();
+            let frame_image =
+                ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, &buffer_content[..]).unwrap();
+            frame_image
+                .save(format!("{}_{:04}.png", output_prefix, frame))
+                .unwrap();
+        }
+        return;
+    } else if let Some(output) = matches.value_of("frame") {
         // Exporting an image
 
         let buf = CpuAccessibleBuffer::from_iter(
@@ -173,6 +377,7 @@ fn main() {
                 height: height as i32,
                 view_position,
                 view_angle,
+                view_pitch,
                 time: now.elapsed().as_secs_f32(),
                 _dummy0: [0, 0, 0, 0, 0, 0, 0, 0],
             },
@@ -193,7 +398,7 @@ fn main() {
         let mut builder = AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
         builder
             .dispatch(
-                [width / 8, height / 8, 1],
+                [(width + 7) / 8, (height + 7) / 8, 1],
                 compute_pipeline.clone(),
                 set.clone(),
                 (),
@@ -218,7 +423,7 @@ fn main() {
         // Creating a window
 
         let surface = WindowBuilder::new()
-            .with_resizable(false)
+            .with_resizable(true)
             .with_inner_size(Size::Physical(PhysicalSize { width, height }))
             .build_vk_surface(&events_loop, instance.clone())
             .unwrap();
@@ -229,9 +434,23 @@ fn main() {
             .expect("failed to get surface capabilities");
         let dimensions = caps.current_extent.unwrap_or([width, height]);
         let alpha = caps.supported_composite_alpha.iter().next().unwrap();
-        let format = caps.supported_formats[0].0;
+        // The shader declares its output image as `rgba8`, which GLSL maps specifically to
+        // R8G8B8A8_UNORM (there is no `bgra8` qualifier) - binding any other format straight
+        // to that descriptor is a format mismatch. Only write directly into the swapchain
+        // image when it's offered in exactly that format; otherwise fall back to rendering
+        // into the intermediate image and blitting, which doesn't care about the swapchain's
+        // format since it's a transfer, not a storage-image binding.
+        let supports_direct_write = caps
+            .supported_formats
+            .iter()
+            .any(|(format, _)| *format == Format::R8G8B8A8Unorm);
+        let format = if supports_direct_write {
+            Format::R8G8B8A8Unorm
+        } else {
+            caps.supported_formats[0].0
+        };
 
-        let (swapchain, images) = Swapchain::new(
+        let (mut swapchain, mut images) = Swapchain::new(
             device.clone(),
             surface.clone(),
             caps.min_image_count,
@@ -241,6 +460,7 @@ fn main() {
             ImageUsage {
                 color_attachment: true,
                 transfer_destination: true,
+                storage: supports_direct_write,
                 ..ImageUsage::none()
             },
             &queue,
@@ -253,6 +473,56 @@ fn main() {
         )
         .expect("failed to create swapchain");
 
+        // Swapchain images start out in an undefined layout. Without ever writing to them
+        // first, handing one straight to `then_swapchain_present` panics with
+        // `AccessError::ImageNotInitialized { requested: PresentSrc }`, so clear each one
+        // once up front to give it a known initial layout.
+        for swapchain_image in &images {
+            let mut builder =
+                AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
+            builder
+                .clear_color_image(swapchain_image.clone(), ClearValue::Float([0.0; 4]))
+                .unwrap();
+            builder
+                .build()
+                .unwrap()
+                .execute(queue.clone())
+                .unwrap()
+                .then_signal_fence_and_flush()
+                .unwrap()
+                .wait(None)
+                .unwrap();
+        }
+
+        let use_intermediate_image = internal_width.is_some() || !supports_direct_write;
+        let mut render_width = internal_width.unwrap_or(width);
+        let mut render_height = internal_height.unwrap_or(height);
+        let mut intermediate_image = if use_intermediate_image {
+            Some(
+                StorageImage::new(
+                    device.clone(),
+                    Dimensions::Dim2d {
+                        width: render_width,
+                        height: render_height,
+                    },
+                    Format::R8G8B8A8Unorm,
+                    Some(queue.family()),
+                )
+                .unwrap(),
+            )
+        } else {
+            None
+        };
+
+        let mut recreate_swapchain = false;
+
+        // Per-swapchain-image fences so we only ever wait on the frame slot we
+        // are about to reuse, rather than stalling on every submission.
+        let mut fences: Vec<Option<Arc<FenceSignalFuture<Box<dyn GpuFuture>>>>> =
+            vec![None; images.len()];
+        let mut previous_fence_i = 0;
+        let mut previous_frame_end: Box<dyn GpuFuture> = sync::now(device.clone()).boxed();
+
         loop {
             // Handle window events
             events_loop.run(move |event, _, control_flow| match event {
@@ -262,6 +532,12 @@ fn main() {
                 } => {
                     *control_flow = ControlFlow::Exit;
                 }
+                Event::WindowEvent {
+                    event: winit::event::WindowEvent::Resized(_),
+                    ..
+                } => {
+                    recreate_swapchain = true;
+                }
                 Event::DeviceEvent {
                     event: DeviceEvent::Key(k),
                     ..
@@ -272,14 +548,14 @@ fn main() {
                         state: ElementState::Pressed,
                         ..
                     } => {
-                        moving_forward = true;
+                        up_pressed = true;
                     }
                     KeyboardInput {
                         virtual_keycode: Some(VirtualKeyCode::Up),
                         state: ElementState::Released,
                         ..
                     } => {
-                        moving_forward = false;
+                        up_pressed = false;
                     }
                     // Check if down arrow is being pressed
                     KeyboardInput {
@@ -287,14 +563,14 @@ fn main() {
                         state: ElementState::Pressed,
                         ..
                     } => {
-                        moving_backward = true;
+                        down_pressed = true;
                     }
                     KeyboardInput {
                         virtual_keycode: Some(VirtualKeyCode::Down),
                         state: ElementState::Released,
                         ..
                     } => {
-                        moving_backward = false;
+                        down_pressed = false;
                     }
                     // Check if left arrow is being pressed
                     KeyboardInput {
@@ -326,17 +602,130 @@ fn main() {
                     } => {
                         turning_right = false;
                     }
+                    // W/S move forward/backward, same as the arrow keys
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::W),
+                        state: ElementState::Pressed,
+                        ..
+                    } => {
+                        w_pressed = true;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::W),
+                        state: ElementState::Released,
+                        ..
+                    } => {
+                        w_pressed = false;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::S),
+                        state: ElementState::Pressed,
+                        ..
+                    } => {
+                        s_pressed = true;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::S),
+                        state: ElementState::Released,
+                        ..
+                    } => {
+                        s_pressed = false;
+                    }
+                    // A/D strafe left/right
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::A),
+                        state: ElementState::Pressed,
+                        ..
+                    } => {
+                        strafing_left = true;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::A),
+                        state: ElementState::Released,
+                        ..
+                    } => {
+                        strafing_left = false;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::D),
+                        state: ElementState::Pressed,
+                        ..
+                    } => {
+                        strafing_right = true;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::D),
+                        state: ElementState::Released,
+                        ..
+                    } => {
+                        strafing_right = false;
+                    }
+                    // Q/E move down/up
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::Q),
+                        state: ElementState::Pressed,
+                        ..
+                    } => {
+                        moving_down = true;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::Q),
+                        state: ElementState::Released,
+                        ..
+                    } => {
+                        moving_down = false;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::E),
+                        state: ElementState::Pressed,
+                        ..
+                    } => {
+                        moving_up = true;
+                    }
+                    KeyboardInput {
+                        virtual_keycode: Some(VirtualKeyCode::E),
+                        state: ElementState::Released,
+                        ..
+                    } => {
+                        moving_up = false;
+                    }
                     _ => {}
                 },
+                Event::DeviceEvent {
+                    event: DeviceEvent::MouseMotion { delta },
+                    ..
+                } => {
+                    view_angle += delta.0 as f32 * MOUSE_SENSITIVITY;
+                    view_pitch = (view_pitch - delta.1 as f32 * MOUSE_SENSITIVITY)
+                        .clamp(-MAX_PITCH, MAX_PITCH);
+                }
                 Event::MainEventsCleared => {
-                    // Handle movement
-                    if moving_forward {
-                        view_position[0] += 0.01 * (view_angle * 0.01745_f32).sin();
-                        view_position[2] -= 0.01 * (view_angle * 0.01745_f32).cos();
+                    // Handle movement. Forward/strafe are derived from the current yaw so
+                    // the camera always moves relative to where it's actually looking.
+                    let yaw_rad = view_angle * 0.01745_f32;
+                    let forward = [yaw_rad.sin(), 0., -yaw_rad.cos()];
+                    let right = [yaw_rad.cos(), 0., yaw_rad.sin()];
+                    if up_pressed || w_pressed {
+                        view_position[0] += 0.01 * forward[0];
+                        view_position[2] += 0.01 * forward[2];
+                    }
+                    if down_pressed || s_pressed {
+                        view_position[0] -= 0.01 * forward[0];
+                        view_position[2] -= 0.01 * forward[2];
+                    }
+                    if strafing_left {
+                        view_position[0] -= 0.01 * right[0];
+                        view_position[2] -= 0.01 * right[2];
+                    }
+                    if strafing_right {
+                        view_position[0] += 0.01 * right[0];
+                        view_position[2] += 0.01 * right[2];
                     }
-                    if moving_backward {
-                        view_position[0] -= 0.01 * (view_angle * 0.01745_f32).sin();
-                        view_position[2] += 0.01 * (view_angle * 0.01745_f32).cos();
+                    if moving_up {
+                        view_position[1] += 0.01;
+                    }
+                    if moving_down {
+                        view_position[1] -= 0.01;
                     }
                     // Handle turning
                     if turning_left {
@@ -346,73 +735,194 @@ fn main() {
                         view_angle += 1.;
                     }
 
-                    let (image_num, _suboptimal, acquire_future) =
-                        vulkano::swapchain::acquire_next_image(swapchain.clone(), None).unwrap();
+                    previous_frame_end.cleanup_finished();
+
+                    if recreate_swapchain {
+                        let new_dimensions = surface.window().inner_size();
+                        width = new_dimensions.width;
+                        height = new_dimensions.height;
+                        render_width = internal_width.unwrap_or(width);
+                        render_height = internal_height.unwrap_or(height);
+
+                        let (new_swapchain, new_images) =
+                            match swapchain.recreate_with_dimensions([width, height]) {
+                                Ok(r) => r,
+                                Err(SwapchainCreationError::UnsupportedDimensions) => return,
+                                Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
+                            };
+                        swapchain = new_swapchain;
+                        images = new_images;
+                        fences = vec![None; images.len()];
+
+                        // Freshly (re)created swapchain images are undefined again too.
+                        for swapchain_image in &images {
+                            let mut builder =
+                                AutoCommandBufferBuilder::new(device.clone(), queue.family())
+                                    .unwrap();
+                            builder
+                                .clear_color_image(
+                                    swapchain_image.clone(),
+                                    ClearValue::Float([0.0; 4]),
+                                )
+                                .unwrap();
+                            builder
+                                .build()
+                                .unwrap()
+                                .execute(queue.clone())
+                                .unwrap()
+                                .then_signal_fence_and_flush()
+                                .unwrap()
+                                .wait(None)
+                                .unwrap();
+                        }
+
+                        if use_intermediate_image {
+                            intermediate_image = Some(
+                                StorageImage::new(
+                                    device.clone(),
+                                    Dimensions::Dim2d {
+                                        width: render_width,
+                                        height: render_height,
+                                    },
+                                    Format::R8G8B8A8Unorm,
+                                    Some(queue.family()),
+                                )
+                                .unwrap(),
+                            );
+                        }
+
+                        recreate_swapchain = false;
+                    }
+
+                    let (image_num, suboptimal, acquire_future) =
+                        match vulkano::swapchain::acquire_next_image(swapchain.clone(), None) {
+                            Ok(r) => r,
+                            Err(AcquireError::OutOfDate) => {
+                                recreate_swapchain = true;
+                                return;
+                            }
+                            Err(e) => panic!("Failed to acquire next image: {:?}", e),
+                        };
+                    if suboptimal {
+                        recreate_swapchain = true;
+                    }
+
+                    // Wait for the slot we're about to reuse, not the frame we just submitted.
+                    if let Some(image_fence) = &fences[image_num] {
+                        image_fence.wait(None).unwrap();
+                    }
 
                     let params_buffer = CpuAccessibleBuffer::from_data(
                         device.clone(),
                         BufferUsage::all(),
                         false,
                         cs::ty::Input {
-                            width: width as i32,
-                            height: height as i32,
+                            width: render_width as i32,
+                            height: render_height as i32,
                             view_position,
                             view_angle,
+                            view_pitch,
                             time: now.elapsed().as_secs_f32(),
                             _dummy0: [0, 0, 0, 0, 0, 0, 0, 0],
                         },
                     )
                     .expect("failed to create params buffer");
                     let layout = compute_pipeline.layout().descriptor_set_layout(0).unwrap();
-                    let set = Arc::new(
-                        PersistentDescriptorSet::start(layout.clone())
-                            .add_image(image.clone())
-                            .unwrap()
-                            .add_buffer(params_buffer)
-                            .unwrap()
-                            .build()
-                            .unwrap(),
-                    );
 
                     let mut builder =
                         AutoCommandBufferBuilder::new(device.clone(), queue.family()).unwrap();
-                    builder
-                        .dispatch(
-                            [width / 8, height / 8, 1],
-                            compute_pipeline.clone(),
-                            set.clone(),
-                            (),
-                        )
-                        .unwrap()
-                        .blit_image(
-                            image.clone(),
-                            [0, 0, 0],
-                            [width as i32, height as i32, 1],
-                            0,
-                            0,
-                            images[image_num].clone(),
-                            [0, 0, 0],
-                            [
-                                images[image_num].dimensions()[0] as i32,
-                                images[image_num].dimensions()[1] as i32,
+
+                    if let Some(intermediate_image) = &intermediate_image {
+                        // An internal resolution was requested, or the swapchain format can't be
+                        // bound as the shader's storage image, so render into the intermediate
+                        // image and blit (upscaling if needed) it into the swapchain image.
+                        let set = Arc::new(
+                            PersistentDescriptorSet::start(layout.clone())
+                                .add_image(intermediate_image.clone())
+                                .unwrap()
+                                .add_buffer(params_buffer)
+                                .unwrap()
+                                .build()
+                                .unwrap(),
+                        );
+                        builder
+                            .dispatch(
+                                [(render_width + 7) / 8, (render_height + 7) / 8, 1],
+                                compute_pipeline.clone(),
+                                set.clone(),
+                                (),
+                            )
+                            .unwrap()
+                            .blit_image(
+                                intermediate_image.clone(),
+                                [0, 0, 0],
+                                [render_width as i32, render_height as i32, 1],
+                                0,
+                                0,
+                                images[image_num].clone(),
+                                [0, 0, 0],
+                                [
+                                    images[image_num].dimensions()[0] as i32,
+                                    images[image_num].dimensions()[1] as i32,
+                                    1,
+                                ],
+                                0,
+                                0,
                                 1,
-                            ],
-                            0,
-                            0,
-                            1,
-                            Filter::Linear,
-                        )
-                        .unwrap();
+                                Filter::Linear,
+                            )
+                            .unwrap();
+                    } else {
+                        // Common case: write the compute output straight into the acquired
+                        // swapchain image, skipping the extra full-screen copy.
+                        let set = Arc::new(
+                            PersistentDescriptorSet::start(layout.clone())
+                                .add_image(images[image_num].clone())
+                                .unwrap()
+                                .add_buffer(params_buffer)
+                                .unwrap()
+                                .build()
+                                .unwrap(),
+                        );
+                        builder
+                            .dispatch(
+                                [(render_width + 7) / 8, (render_height + 7) / 8, 1],
+                                compute_pipeline.clone(),
+                                set.clone(),
+                                (),
+                            )
+                            .unwrap();
+                    }
                     let command_buffer = builder.build().unwrap();
 
-                    acquire_future
+                    let previous_future = match fences[previous_fence_i].clone() {
+                        Some(fence) => fence.boxed(),
+                        None => std::mem::replace(
+                            &mut previous_frame_end,
+                            sync::now(device.clone()).boxed(),
+                        ),
+                    };
+
+                    let future = previous_future
+                        .join(acquire_future)
                         .then_execute(queue.clone(), command_buffer)
                         .unwrap()
                         .then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
-                        .then_signal_fence_and_flush()
-                        .unwrap()
-                        .wait(None)
-                        .unwrap();
+                        .boxed()
+                        .then_signal_fence_and_flush();
+
+                    fences[image_num] = match future {
+                        Ok(value) => Some(Arc::new(value)),
+                        Err(FlushError::OutOfDate) => {
+                            recreate_swapchain = true;
+                            None
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to flush future: {:?}", e);
+                            None
+                        }
+                    };
+                    previous_fence_i = image_num;
                 }
                 _ => (),
             });